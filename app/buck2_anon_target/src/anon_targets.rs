@@ -0,0 +1,290 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Bounded concurrency for anon target evaluation.
+//!
+//! [`AnonTargetsRegistry`] is what `anon_target()`/`anon_targets()` (see
+//! `starlark_defs.rs`) register against: each call records one or more
+//! rule/attrs keys against the `StarlarkPromise` it returned to Starlark.
+//! [`AnonTargetsLimiter`] is the cap [`AnonTargetsRegistry::resolve`] uses
+//! so a single `anon_targets()` batch of thousands of keys is evaluated in
+//! bounded waves instead of all at once, while keys shared between
+//! registrations (same rule + attrs) still only run once.
+//!
+//! `resolve`'s `eval_one` closure runs directly against the Starlark heap
+//! that registered it, so it captures non-`Send`, non-`'static` `Value<'v>`s
+//! (the rule's attrs). [`AnonTargetsLimiter::evaluate_all`] therefore bounds
+//! concurrency by polling up to `max_concurrent` of those futures within the
+//! calling task (`buffer_unordered`), rather than by spawning them onto the
+//! runtime, since spawning would require `Send + 'static` and could never be
+//! driven by real anon target evaluation.
+//!
+//! Like the `#[cfg(fbcode_build)]` half of `x2p.rs`, `resolve` itself has no
+//! caller in this checkout: it's the entry point the analysis engine invokes
+//! once all anon targets for an evaluation are registered, and that driver
+//! lives in `buck2_build_api`, outside this crate.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+use allocative::Allocative;
+use buck2_interpreter::starlark_promise::StarlarkPromise;
+use buck2_interpreter_for_build::rule::FrozenRuleCallable;
+use futures::stream;
+use futures::StreamExt;
+use starlark::values::dict::DictOf;
+use starlark::values::Value;
+use starlark::values::ValueTyped;
+
+/// Default cap on how many distinct anon target keys are evaluated
+/// concurrently across all registrations resolved together by
+/// [`AnonTargetsRegistry::resolve`], used when the caller
+/// didn't resolve an `anon_targets.max_concurrent_evaluations` override from
+/// `.buckconfig`. Chosen to bound memory/DICE pressure from a single rule
+/// fanning out thousands of anon targets, while still giving good overlap
+/// for the common case of a few dozen.
+const DEFAULT_MAX_CONCURRENT_EVALUATIONS: usize = 64;
+
+/// The hash of a rule + its attributes, used to key shared anon target
+/// computations: two distinct callers asking for the same rule and
+/// attributes share a single in-flight evaluation.
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Allocative)]
+pub struct AnonTargetKey(pub(crate) u64);
+
+impl AnonTargetKey {
+    fn new<'v>(
+        rule: ValueTyped<'v, FrozenRuleCallable>,
+        attrs: &DictOf<'v, &'v str, Value<'v>>,
+    ) -> anyhow::Result<Self> {
+        // `rule` is a frozen value: a given `.bzl` rule symbol has the same
+        // pointer for the lifetime of the frozen module it came from, so
+        // hashing its identity is a valid, stable proxy for the rule itself.
+        // `attrs`, on the other hand, is an ordinary dict whose iteration
+        // order reflects insertion order, not content -- two calls with the
+        // same attributes in a different order must still collapse to one
+        // key, so sort by attr name before hashing.
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        rule.to_value().ptr_value().hash(&mut hasher);
+        let mut entries: Vec<(&str, Value)> = attrs.collect_entries();
+        entries.sort_by_key(|(k, _)| *k);
+        for (k, v) in entries {
+            k.hash(&mut hasher);
+            v.to_string().hash(&mut hasher);
+        }
+        Ok(Self(hasher.finish()))
+    }
+}
+
+/// Caps how many distinct anon target keys are evaluated concurrently.
+/// Deduplicated keys (same rule + attrs) still share a single in-flight
+/// computation regardless of this limit -- the limit only bounds how many
+/// *distinct* computations are admitted at once, so a rule that fans out
+/// thousands of anon targets is evaluated in bounded waves instead of all
+/// at once.
+#[derive(Debug, Allocative)]
+pub(crate) struct AnonTargetsLimiter {
+    max_concurrent: usize,
+}
+
+impl AnonTargetsLimiter {
+    fn new(max_concurrent: usize) -> Self {
+        Self {
+            max_concurrent: max_concurrent.max(1),
+        }
+    }
+
+    /// Applies [`DEFAULT_MAX_CONCURRENT_EVALUATIONS`] when the caller has no
+    /// override. `max_concurrent_evaluations` is expected to already be
+    /// resolved from `anon_targets.max_concurrent_evaluations` in
+    /// `.buckconfig` by the caller that constructs the registry -- reading
+    /// the daemon config itself isn't this module's job.
+    fn from_daemon_config(max_concurrent_evaluations: Option<usize>) -> Self {
+        Self::new(max_concurrent_evaluations.unwrap_or(DEFAULT_MAX_CONCURRENT_EVALUATIONS))
+    }
+
+    /// Runs one evaluation of `eval_one` per distinct key in `keys`, with at
+    /// most `max_concurrent` in flight at a time, and returns the results in
+    /// the same order as `keys`.
+    ///
+    /// Each distinct key's future is polled concurrently with up to
+    /// `max_concurrent` others (a bounded wave, not one-at-a-time) via
+    /// `buffer_unordered`, all within this call -- nothing is spawned onto
+    /// the runtime, so `eval_one` is free to borrow non-`Send`, non-`'static`
+    /// Starlark heap values. Duplicate keys are only evaluated once; all
+    /// requesters of that key share the one result.
+    pub(crate) async fn evaluate_all<T, F, Fut>(
+        &self,
+        keys: Vec<AnonTargetKey>,
+        eval_one: F,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: Clone,
+        F: Fn(AnonTargetKey) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        // Map each request to a slot in `unique`, so duplicate keys share a
+        // single evaluation below instead of being evaluated twice.
+        let mut unique: Vec<AnonTargetKey> = Vec::new();
+        let mut slot_of_key: HashMap<AnonTargetKey, usize> = HashMap::new();
+        let mut slot_for_request = Vec::with_capacity(keys.len());
+        for key in keys {
+            let slot = *slot_of_key.entry(key.clone()).or_insert_with(|| {
+                unique.push(key);
+                unique.len() - 1
+            });
+            slot_for_request.push(slot);
+        }
+
+        let mut unique_results: Vec<Option<T>> = (0..unique.len()).map(|_| None).collect();
+        let eval_one = &eval_one;
+        let mut completed = stream::iter(unique.into_iter().enumerate())
+            .map(|(slot, key)| async move { (slot, eval_one(key).await) })
+            .buffer_unordered(self.max_concurrent);
+        while let Some((slot, result)) = completed.next().await {
+            unique_results[slot] = Some(result?);
+        }
+
+        Ok(slot_for_request
+            .into_iter()
+            .map(|slot| {
+                unique_results[slot]
+                    .clone()
+                    .expect("every unique slot is visited exactly once above")
+            })
+            .collect())
+    }
+}
+
+/// One `anon_target()`/`anon_targets()` call: the promise returned to
+/// Starlark, and the key(s) it's waiting on.
+#[derive(Debug, Allocative)]
+struct PendingAnonTarget<'v> {
+    promise: ValueTyped<'v, StarlarkPromise<'v>>,
+    keys: Vec<AnonTargetKey>,
+}
+
+/// Tracks anon targets registered during one analysis, and evaluates them
+/// with bounded concurrency once registration is complete.
+///
+/// `register_one`/`register_many` are synchronous bookkeeping only -- they
+/// just record the key(s) a promise is waiting on, so the `StarlarkPromise`
+/// returned to Starlark is created unresolved exactly as before this
+/// registry existed. The actual (async, DICE-backed) evaluation and promise
+/// resolution happens in [`AnonTargetsRegistry::resolve`], which the
+/// analysis engine calls once all anon targets for the evaluation have been
+/// registered.
+#[derive(Debug, Allocative)]
+pub struct AnonTargetsRegistry<'v> {
+    pending: Vec<PendingAnonTarget<'v>>,
+    limiter: AnonTargetsLimiter,
+}
+
+impl<'v> AnonTargetsRegistry<'v> {
+    pub fn new(max_concurrent_evaluations: Option<usize>) -> Self {
+        Self {
+            pending: Vec::new(),
+            limiter: AnonTargetsLimiter::from_daemon_config(max_concurrent_evaluations),
+        }
+    }
+
+    /// Converts the type-erased registry slot that `AnalysisActions` stores
+    /// per-evaluation back into the concrete registry. Kept fallible (and a
+    /// real trait, rather than an inherent method) so a future registry
+    /// implementation could be swapped in without changing call sites.
+    pub fn downcast_mut<'a>(
+        it: &'a mut dyn AnonTargetsRegistryDyn<'v>,
+    ) -> anyhow::Result<&'a mut Self> {
+        Ok(it.as_anon_targets_registry_mut())
+    }
+
+    pub fn anon_target_key(
+        &mut self,
+        rule: ValueTyped<'v, FrozenRuleCallable>,
+        attrs: DictOf<'v, &'v str, Value<'v>>,
+    ) -> anyhow::Result<AnonTargetKey> {
+        AnonTargetKey::new(rule, &attrs)
+    }
+
+    pub fn register_one(
+        &mut self,
+        promise: ValueTyped<'v, StarlarkPromise<'v>>,
+        key: AnonTargetKey,
+    ) -> anyhow::Result<()> {
+        self.pending.push(PendingAnonTarget {
+            promise,
+            keys: vec![key],
+        });
+        Ok(())
+    }
+
+    pub fn register_many(
+        &mut self,
+        promise: ValueTyped<'v, StarlarkPromise<'v>>,
+        rules: Vec<(
+            ValueTyped<'v, FrozenRuleCallable>,
+            DictOf<'v, &'v str, Value<'v>>,
+        )>,
+    ) -> anyhow::Result<()> {
+        let keys = rules
+            .into_iter()
+            .map(|(rule, attrs)| AnonTargetKey::new(rule, &attrs))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.pending.push(PendingAnonTarget { promise, keys });
+        Ok(())
+    }
+
+    /// Evaluates every key registered via `register_one`/`register_many`,
+    /// bounded by this registry's concurrency limit, and returns each
+    /// pending registration's promise alongside its result(s), in
+    /// registration order, ready for the caller to resolve against the
+    /// evaluator's heap.
+    pub async fn resolve<T, F, Fut>(
+        &mut self,
+        eval_one: F,
+    ) -> anyhow::Result<Vec<(ValueTyped<'v, StarlarkPromise<'v>>, Vec<T>)>>
+    where
+        T: Clone,
+        F: Fn(AnonTargetKey) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let pending = std::mem::take(&mut self.pending);
+
+        let all_keys: Vec<AnonTargetKey> = pending
+            .iter()
+            .flat_map(|p| p.keys.iter().cloned())
+            .collect();
+        let all_results = self.limiter.evaluate_all(all_keys, eval_one).await?;
+
+        // `all_results` is parallel to `all_keys` above; slice it back up
+        // per-registration in the same order.
+        let mut all_results = all_results.into_iter();
+        let mut out = Vec::with_capacity(pending.len());
+        for p in pending {
+            let results = all_results.by_ref().take(p.keys.len()).collect();
+            out.push((p.promise, results));
+        }
+        Ok(out)
+    }
+}
+
+/// Object-safe view of [`AnonTargetsRegistry`] that `AnalysisActions` stores
+/// behind, so this crate's registration logic stays decoupled from the
+/// analysis action state that owns it.
+pub trait AnonTargetsRegistryDyn<'v>: Debug {
+    fn as_anon_targets_registry_mut(&mut self) -> &mut AnonTargetsRegistry<'v>;
+}
+
+impl<'v> AnonTargetsRegistryDyn<'v> for AnonTargetsRegistry<'v> {
+    fn as_anon_targets_registry_mut(&mut self) -> &mut AnonTargetsRegistry<'v> {
+        self
+    }
+}
@@ -0,0 +1,154 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Parsing and matching for the conventional `NO_PROXY`/`no_proxy`
+//! environment variable, used to exclude specific hosts from a proxy that
+//! was otherwise selected via `HTTP_PROXY`/`HTTPS_PROXY`/`ALL_PROXY`.
+
+use std::net::IpAddr;
+
+/// A parsed `NO_PROXY` rule list. Either "proxy everything" (the variable
+/// wasn't set or was empty), "proxy nothing" (a literal `*`), or a list of
+/// host/suffix/CIDR rules to match a request URI against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum NoProxy {
+    MatchNone,
+    MatchAll,
+    Rules(Vec<NoProxyRule>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NoProxyRule {
+    host: HostMatch,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostMatch {
+    /// Matches a host equal to, or a subdomain of, this suffix.
+    /// e.g. `example.com` matches `example.com` and `a.example.com`.
+    DomainSuffix(String),
+    Cidr(ipnet::IpNet),
+}
+
+impl NoProxy {
+    /// Reads and parses `NO_PROXY`, falling back to `no_proxy`.
+    pub(crate) fn from_env() -> Self {
+        let var = std::env::var("NO_PROXY")
+            .ok()
+            .or_else(|| std::env::var("no_proxy").ok());
+        match var {
+            Some(v) => Self::parse(&v),
+            None => Self::MatchNone,
+        }
+    }
+
+    pub(crate) fn parse(value: &str) -> Self {
+        let value = value.trim();
+        if value.is_empty() {
+            return Self::MatchNone;
+        }
+        if value == "*" {
+            return Self::MatchAll;
+        }
+
+        let rules = value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(NoProxyRule::parse)
+            .collect();
+        Self::Rules(rules)
+    }
+
+    /// Returns `true` if `host:port` should bypass the proxy. Unlike
+    /// `NO_PROXY` entries (which may omit a port to match any port), `port`
+    /// here must already reflect the request's actual port, defaulted by
+    /// the caller from its URI scheme (e.g. 443 for `https`) -- this module
+    /// has no business guessing a scheme-dependent default.
+    pub(crate) fn matches_host_port(&self, host: &str, port: u16) -> bool {
+        match self {
+            Self::MatchNone => false,
+            Self::MatchAll => true,
+            Self::Rules(rules) => rules.iter().any(|rule| rule.matches(host, port)),
+        }
+    }
+}
+
+impl NoProxyRule {
+    fn parse(entry: &str) -> Self {
+        // Try the whole entry as a bare IP/CIDR first: an unbracketed IPv6
+        // address or range (e.g. `2001:db8::1`, `2001:db8::/32`) contains
+        // colons that aren't a `:port` suffix, and would otherwise be
+        // mis-split by the `host:port` handling below.
+        if let Some(host) = host_match(entry) {
+            return Self { host, port: None };
+        }
+
+        // `[2001:db8::1]:8080` / `[2001:db8::1]` -- bracketed IPv6, with an
+        // optional port.
+        if let Some(rest) = entry.strip_prefix('[') {
+            if let Some((host, rest)) = rest.split_once(']') {
+                let port = rest.strip_prefix(':').and_then(|p| p.parse().ok());
+                return Self {
+                    host: host_match(host)
+                        .unwrap_or_else(|| HostMatch::DomainSuffix(host.to_ascii_lowercase())),
+                    port,
+                };
+            }
+        }
+
+        // `example.com:8080` / `10.0.0.1:8080` -- domain names and IPv4
+        // addresses can't themselves contain a `:`, so splitting on the
+        // last one is unambiguous here.
+        let (host, port) = match entry.rsplit_once(':') {
+            Some((host, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => {
+                (host, port.parse::<u16>().ok())
+            }
+            _ => (entry, None),
+        };
+        // A leading `.` is equivalent to a bare domain suffix match.
+        let host = host.strip_prefix('.').unwrap_or(host);
+
+        Self {
+            host: host_match(host).unwrap_or_else(|| HostMatch::DomainSuffix(host.to_ascii_lowercase())),
+            port,
+        }
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        if let Some(rule_port) = self.port {
+            if rule_port != port {
+                return false;
+            }
+        }
+
+        match &self.host {
+            HostMatch::DomainSuffix(suffix) => {
+                let host = host.to_ascii_lowercase();
+                host == *suffix || host.ends_with(&format!(".{}", suffix))
+            }
+            HostMatch::Cidr(cidr) => host
+                .parse::<IpAddr>()
+                .map(|ip| cidr.contains(&ip))
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Parses `s` as a bare IP address or CIDR range, if it is one.
+fn host_match(s: &str) -> Option<HostMatch> {
+    if let Ok(cidr) = s.parse::<ipnet::IpNet>() {
+        Some(HostMatch::Cidr(cidr))
+    } else if let Ok(ip) = s.parse::<IpAddr>() {
+        Some(HostMatch::Cidr(ipnet::IpNet::from(ip)))
+    } else {
+        None
+    }
+}
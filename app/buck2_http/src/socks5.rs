@@ -0,0 +1,348 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! A minimal SOCKS5 (RFC 1928 / RFC 1929) connector, used so buck2's HTTP
+//! client can route traffic through a SOCKS5 gateway (e.g. a corporate SOCKS
+//! egress, or a local Tor/onion forwarder) in addition to the HTTP CONNECT
+//! and unix socket proxies in `x2p`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use anyhow::Context as _;
+use http::Uri;
+use hyper::client::connect::Connected;
+use hyper::client::connect::Connection;
+use hyper::service::Service;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::ReadBuf;
+use tokio::net::TcpStream;
+
+const SOCKS5_VERSION: u8 = 0x05;
+
+const AUTH_NONE: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Credentials used for SOCKS5 username/password authentication (RFC 1929).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Auth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Address and optional credentials of a SOCKS5 proxy, as discovered from a
+/// `socks5://[user:pass@]host:port` URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5ProxyConfig {
+    pub host: String,
+    pub port: u16,
+    pub auth: Option<Socks5Auth>,
+}
+
+impl Socks5ProxyConfig {
+    /// Parses a `socks5://[user:pass@]host:port` URI into a proxy config.
+    pub fn parse(uri: &str) -> anyhow::Result<Self> {
+        let uri: Uri = uri
+            .parse()
+            .with_context(|| format!("Invalid SOCKS5 proxy URI: `{}`", uri))?;
+
+        let scheme = uri.scheme_str().unwrap_or_default();
+        if scheme != "socks5" && scheme != "socks5h" {
+            return Err(anyhow::anyhow!(
+                "Expected a `socks5://` or `socks5h://` URI, got `{}`",
+                uri
+            ));
+        }
+
+        let authority = uri
+            .authority()
+            .ok_or_else(|| anyhow::anyhow!("SOCKS5 proxy URI `{}` is missing a host", uri))?;
+
+        let host = authority.host().to_owned();
+        let port = authority
+            .port_u16()
+            .ok_or_else(|| anyhow::anyhow!("SOCKS5 proxy URI `{}` is missing a port", uri))?;
+
+        let auth = match authority.as_str().split_once('@') {
+            Some((userinfo, _)) => {
+                let (username, password) = userinfo
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("SOCKS5 proxy credentials must be `user:pass`"))?;
+                Some(Socks5Auth {
+                    username: username.to_owned(),
+                    password: password.to_owned(),
+                })
+            }
+            None => None,
+        };
+
+        Ok(Self { host, port, auth })
+    }
+}
+
+/// A `hyper`/`tower` compatible connector that tunnels connections through a
+/// SOCKS5 proxy, performing the handshake and `CONNECT` command before
+/// handing back a connected stream.
+#[derive(Debug, Clone)]
+pub struct Socks5Connector {
+    proxy: Socks5ProxyConfig,
+}
+
+impl Socks5Connector {
+    pub fn new(proxy: Socks5ProxyConfig) -> Self {
+        Self { proxy }
+    }
+
+    async fn connect(proxy: Socks5ProxyConfig, dst: Uri) -> anyhow::Result<Socks5Stream> {
+        let host = dst
+            .host()
+            .ok_or_else(|| anyhow::anyhow!("URI `{}` has no host to connect to", dst))?
+            .to_owned();
+        let port = dst.port_u16().unwrap_or(match dst.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+
+        let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port))
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to connect to SOCKS5 proxy at {}:{}",
+                    proxy.host, proxy.port
+                )
+            })?;
+
+        handshake(&mut stream, proxy.auth.as_ref()).await?;
+        connect_command(&mut stream, &host, port).await?;
+
+        Ok(Socks5Stream { inner: stream })
+    }
+}
+
+/// Sends the greeting, advertising the auth methods we support, and then
+/// performs username/password auth (RFC 1929) if the proxy requires it.
+async fn handshake(stream: &mut TcpStream, auth: Option<&Socks5Auth>) -> anyhow::Result<()> {
+    let methods: &[u8] = if auth.is_some() {
+        &[AUTH_NONE, AUTH_USERNAME_PASSWORD]
+    } else {
+        &[AUTH_NONE]
+    };
+
+    let mut greeting = Vec::with_capacity(2 + methods.len());
+    greeting.push(SOCKS5_VERSION);
+    greeting.push(methods.len() as u8);
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(anyhow::anyhow!(
+            "SOCKS5 proxy replied with unsupported version {}",
+            reply[0]
+        ));
+    }
+
+    match reply[1] {
+        AUTH_NONE => Ok(()),
+        AUTH_USERNAME_PASSWORD => {
+            let auth = auth.ok_or_else(|| {
+                anyhow::anyhow!("SOCKS5 proxy requires username/password authentication")
+            })?;
+            username_password_auth(stream, auth).await
+        }
+        AUTH_NO_ACCEPTABLE_METHODS => Err(anyhow::anyhow!(
+            "SOCKS5 proxy did not accept any of our authentication methods"
+        )),
+        other => Err(anyhow::anyhow!(
+            "SOCKS5 proxy selected unknown authentication method {}",
+            other
+        )),
+    }
+}
+
+async fn username_password_auth(stream: &mut TcpStream, auth: &Socks5Auth) -> anyhow::Result<()> {
+    if auth.username.len() > 255 || auth.password.len() > 255 {
+        return Err(anyhow::anyhow!(
+            "SOCKS5 username/password must each be at most 255 bytes"
+        ));
+    }
+
+    let mut req = Vec::with_capacity(3 + auth.username.len() + auth.password.len());
+    req.push(0x01); // sub-negotiation version
+    req.push(auth.username.len() as u8);
+    req.extend_from_slice(auth.username.as_bytes());
+    req.push(auth.password.len() as u8);
+    req.extend_from_slice(auth.password.as_bytes());
+    stream.write_all(&req).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(anyhow::anyhow!(
+            "SOCKS5 proxy rejected username/password authentication"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Issues the `CONNECT` command for `host:port` and reads back the bind
+/// reply, surfacing the SOCKS5 error code on failure.
+async fn connect_command(stream: &mut TcpStream, host: &str, port: u16) -> anyhow::Result<()> {
+    let mut req = vec![SOCKS5_VERSION, CMD_CONNECT, 0x00];
+    if let Ok(ipv4) = host.parse::<std::net::Ipv4Addr>() {
+        req.push(ATYP_IPV4);
+        req.extend_from_slice(&ipv4.octets());
+    } else if let Ok(ipv6) = host.parse::<std::net::Ipv6Addr>() {
+        req.push(ATYP_IPV6);
+        req.extend_from_slice(&ipv6.octets());
+    } else {
+        if host.len() > 255 {
+            return Err(anyhow::anyhow!("SOCKS5 target host name is too long"));
+        }
+        req.push(ATYP_DOMAIN);
+        req.push(host.len() as u8);
+        req.extend_from_slice(host.as_bytes());
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+
+    stream.write_all(&req).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != SOCKS5_VERSION {
+        return Err(anyhow::anyhow!(
+            "SOCKS5 proxy replied with unsupported version {} to CONNECT",
+            head[0]
+        ));
+    }
+    if head[1] != 0x00 {
+        return Err(anyhow::anyhow!(
+            "SOCKS5 CONNECT to {}:{} failed: {}",
+            host,
+            port,
+            describe_reply_code(head[1])
+        ));
+    }
+
+    // Consume and discard the bound address in the reply, whose length
+    // depends on the address type.
+    match head[3] {
+        ATYP_IPV4 => {
+            let mut buf = [0u8; 4 + 2];
+            stream.read_exact(&mut buf).await?;
+        }
+        ATYP_IPV6 => {
+            let mut buf = [0u8; 16 + 2];
+            stream.read_exact(&mut buf).await?;
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut buf = vec![0u8; len[0] as usize + 2];
+            stream.read_exact(&mut buf).await?;
+        }
+        other => {
+            return Err(anyhow::anyhow!(
+                "SOCKS5 proxy returned unknown address type {} in CONNECT reply",
+                other
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn describe_reply_code(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// A connected stream tunnelled through a SOCKS5 proxy.
+#[derive(Debug)]
+pub struct Socks5Stream {
+    inner: TcpStream,
+}
+
+impl Connection for Socks5Stream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for Socks5Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Socks5Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Service<Uri> for Socks5Connector {
+    type Response = Socks5Stream;
+    type Error = anyhow::Error;
+    type Future = Pin<Box<dyn Future<Output = anyhow::Result<Socks5Stream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let proxy = self.proxy.clone();
+        Box::pin(Self::connect(proxy, dst))
+    }
+}
@@ -0,0 +1,392 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! A `tower` layer that wraps every outbound HTTP request in a structured
+//! tracing span, so a single request can be correlated end-to-end with its
+//! outcome (proxy chosen, status, bytes transferred, `X2PAgentError`, ...)
+//! instead of relying on ad-hoc `tracing::debug!` lines.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Instant;
+
+use http::HeaderMap;
+use http::Request;
+use http::Response;
+use tower::Layer;
+use tower::Service;
+use tracing::Instrument;
+use tracing::Span;
+
+use crate::x2p::X2PAgentError;
+
+/// Header names that must never be recorded on a span or otherwise logged.
+const REDACTED_HEADER_PREFIXES: &[&str] = &["x-fb-validated-x2pauth-"];
+const REDACTED_HEADERS: &[&str] = &["authorization"];
+
+/// Returns `true` if `name` is sensitive and must never be emitted in logs
+/// or tracing fields.
+pub fn is_redacted_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    REDACTED_HEADERS.contains(&name.as_str())
+        || REDACTED_HEADER_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+/// Output format for the HTTP tracing subsystem, mirroring the compact vs.
+/// pretty switch offered by mature tracing setups (e.g. `tracing-subscriber`'s
+/// own formatters).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpTraceFormat {
+    /// One line per request:
+    /// `request_id=1 method=GET host=example.com path=/ proxy=http status=200 ...`.
+    #[default]
+    Compact,
+    /// One field per line, easier to scan when debugging a single request.
+    Pretty,
+}
+
+/// Configuration for the HTTP tracing layer: whether it's enabled at all,
+/// at what level spans are emitted, and how they're formatted.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpTraceConfig {
+    pub level: Option<tracing::Level>,
+    pub format: HttpTraceFormat,
+}
+
+impl Default for HttpTraceConfig {
+    fn default() -> Self {
+        Self {
+            level: Some(tracing::Level::DEBUG),
+            format: HttpTraceFormat::default(),
+        }
+    }
+}
+
+impl HttpTraceConfig {
+    /// Disables the HTTP tracing subsystem entirely.
+    pub fn silent() -> Self {
+        Self {
+            level: None,
+            format: HttpTraceFormat::default(),
+        }
+    }
+}
+
+/// Monotonically increasing ID assigned to each outbound request, used to
+/// correlate the request span with whatever else logs alongside it.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+fn next_request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A `tower::Layer` that wraps a client service (one taking `http::Request`s)
+/// with the request tracing span described above.
+#[derive(Debug, Clone)]
+pub struct TraceLayer {
+    config: HttpTraceConfig,
+    proxy_kind: &'static str,
+}
+
+impl TraceLayer {
+    pub fn new(config: HttpTraceConfig, proxy_kind: &'static str) -> Self {
+        Self { config, proxy_kind }
+    }
+}
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = TraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceService {
+            inner,
+            config: self.config,
+            proxy_kind: self.proxy_kind,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceService<S> {
+    inner: S,
+    config: HttpTraceConfig,
+    proxy_kind: &'static str,
+}
+
+impl<S, ReqBody, RespBody> Service<Request<ReqBody>> for TraceService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<RespBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: std::fmt::Display,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let Some(level) = self.config.level else {
+            // Tracing is silenced: fall straight through, no span, no overhead.
+            let fut = self.inner.call(req);
+            return Box::pin(fut);
+        };
+
+        let request_id = next_request_id();
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+        let format = self.config.format;
+        let proxy_kind = self.proxy_kind;
+        // Header *names* only, and only non-sensitive ones, so a pretty-mode
+        // dump of "what did we send" can never leak `Authorization` or an
+        // x2pauth token even if someone extends this later to include values.
+        let request_header_names = loggable_header_names(req.headers());
+
+        let span = make_span(level, request_id, &method, &uri, proxy_kind);
+
+        // `poll_ready` above was only checked on `self.inner`, not on a clone
+        // of it -- tower only guarantees readiness for the instance that was
+        // actually polled. Swap a fresh clone into `self.inner` for next
+        // time, and dispatch this call on the already-ready instance we just
+        // swapped out.
+        let mut svc = self.inner.clone();
+        std::mem::swap(&mut svc, &mut self.inner);
+        let start = Instant::now();
+
+        Box::pin(
+            async move {
+                let result = svc.call(req).await;
+                let elapsed = start.elapsed();
+                Span::current().record("elapsed_ms", elapsed.as_millis() as u64);
+
+                let mut outcome = RequestOutcome {
+                    request_id,
+                    method,
+                    uri,
+                    proxy_kind,
+                    elapsed,
+                    status: None,
+                    bytes: None,
+                    x2p_error: None,
+                    error: None,
+                    request_header_names,
+                    response_header_names: Vec::new(),
+                };
+
+                match &result {
+                    Ok(resp) => {
+                        Span::current().record("status", resp.status().as_u16());
+                        outcome.status = Some(resp.status());
+                        outcome.response_header_names = loggable_header_names(resp.headers());
+                        if let Some(len) = content_length(resp.headers()) {
+                            Span::current().record("bytes", len);
+                            outcome.bytes = Some(len);
+                        }
+                        if let Some(err) = X2PAgentError::from_headers(&outcome.uri, resp.headers())
+                        {
+                            record_x2p_error(&err);
+                            outcome.x2p_error = Some(err);
+                        }
+                    }
+                    Err(e) => {
+                        outcome.error = Some(e.to_string());
+                    }
+                }
+
+                log_outcome(level, format, &outcome);
+
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Everything needed to render one request's completion line, regardless of
+/// [`HttpTraceFormat`].
+struct RequestOutcome<E> {
+    request_id: u64,
+    method: http::Method,
+    uri: http::Uri,
+    proxy_kind: &'static str,
+    elapsed: std::time::Duration,
+    status: Option<http::StatusCode>,
+    bytes: Option<u64>,
+    x2p_error: Option<X2PAgentError>,
+    error: Option<E>,
+    request_header_names: Vec<String>,
+    response_header_names: Vec<String>,
+}
+
+/// Names (never values) of the non-redacted headers on `headers`, i.e. with
+/// `Authorization` and `x-fb-validated-x2pauth-*` filtered out. This is the
+/// only place the tracing layer emits anything about headers directly; the
+/// other header reads (`Content-Length`, and the x2pagent headers via
+/// `X2PAgentError::from_headers`) only emit the parsed length or error, so
+/// filtering here is what backs the "never emitted" guarantee.
+fn loggable_header_names(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .keys()
+        .map(|name| name.as_str())
+        .filter(|name| !is_redacted_header(name))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Renders and emits the completion line for a request, in compact
+/// (single-line) or pretty (one field per line) form, at `level`.
+fn log_outcome(level: tracing::Level, format: HttpTraceFormat, outcome: &RequestOutcome<String>) {
+    let message = match format {
+        HttpTraceFormat::Compact => format!(
+            "request_id={} method={} host={} path={}{}{}{}{}",
+            outcome.request_id,
+            outcome.method,
+            outcome.uri.host().unwrap_or(""),
+            outcome.uri.path(),
+            field(" proxy=", &Some(outcome.proxy_kind)),
+            field(" status=", &outcome.status.map(|s| s.as_u16())),
+            field(" elapsed_ms=", &Some(outcome.elapsed.as_millis())),
+            field(" error=", &outcome.error),
+        ),
+        HttpTraceFormat::Pretty => {
+            let mut lines = vec![
+                "HTTP request completed:".to_owned(),
+                format!("  request_id: {}", outcome.request_id),
+                format!("  method:     {}", outcome.method),
+                format!("  host:       {}", outcome.uri.host().unwrap_or("")),
+                format!("  path:       {}", outcome.uri.path()),
+                format!("  proxy:      {}", outcome.proxy_kind),
+                format!("  elapsed_ms: {}", outcome.elapsed.as_millis()),
+            ];
+            if let Some(status) = outcome.status {
+                lines.push(format!("  status:     {}", status.as_u16()));
+            }
+            if let Some(bytes) = outcome.bytes {
+                lines.push(format!("  bytes:      {}", bytes));
+            }
+            if let Some(err) = &outcome.x2p_error {
+                lines.push(format!("  x2p_error:  {}", err));
+            }
+            if let Some(err) = &outcome.error {
+                lines.push(format!("  error:      {}", err));
+            }
+            if !outcome.request_header_names.is_empty() {
+                lines.push(format!(
+                    "  req headers:  {}",
+                    outcome.request_header_names.join(", ")
+                ));
+            }
+            if !outcome.response_header_names.is_empty() {
+                lines.push(format!(
+                    "  resp headers: {}",
+                    outcome.response_header_names.join(", ")
+                ));
+            }
+            lines.join("\n")
+        }
+    };
+
+    macro_rules! emit {
+        ($lvl:expr) => {
+            tracing::event!($lvl, "{}", message)
+        };
+    }
+    match level {
+        tracing::Level::ERROR => emit!(tracing::Level::ERROR),
+        tracing::Level::WARN => emit!(tracing::Level::WARN),
+        tracing::Level::INFO => emit!(tracing::Level::INFO),
+        tracing::Level::DEBUG => emit!(tracing::Level::DEBUG),
+        tracing::Level::TRACE => emit!(tracing::Level::TRACE),
+    }
+}
+
+/// Renders `" key=value"` for the compact format, or nothing if `value` is
+/// absent -- used so optional fields don't leave a dangling `key=` behind.
+fn field<T: std::fmt::Display>(key: &str, value: &Option<T>) -> String {
+    match value {
+        Some(v) => format!("{}{}", key, v),
+        None => String::new(),
+    }
+}
+
+fn make_span(
+    level: tracing::Level,
+    request_id: u64,
+    method: &http::Method,
+    uri: &http::Uri,
+    proxy_kind: &str,
+) -> Span {
+    macro_rules! new_span {
+        ($lvl:expr) => {
+            tracing::span!(
+                $lvl,
+                "http_request",
+                request_id,
+                method = %method,
+                scheme = uri.scheme_str().unwrap_or(""),
+                host = uri.host().unwrap_or(""),
+                path = uri.path(),
+                proxy = proxy_kind,
+                status = tracing::field::Empty,
+                bytes = tracing::field::Empty,
+                elapsed_ms = tracing::field::Empty,
+                x2p_error = tracing::field::Empty,
+                x2p_error_host = tracing::field::Empty,
+                x2p_error_path = tracing::field::Empty,
+            )
+        };
+    }
+
+    match level {
+        tracing::Level::ERROR => new_span!(tracing::Level::ERROR),
+        tracing::Level::WARN => new_span!(tracing::Level::WARN),
+        tracing::Level::INFO => new_span!(tracing::Level::INFO),
+        tracing::Level::DEBUG => new_span!(tracing::Level::DEBUG),
+        tracing::Level::TRACE => new_span!(tracing::Level::TRACE),
+    }
+}
+
+fn record_x2p_error(err: &X2PAgentError) {
+    let span = Span::current();
+    match err {
+        X2PAgentError::ForbiddenHost { host, .. } => {
+            span.record("x2p_error", "forbidden_host");
+            span.record("x2p_error_host", host.as_str());
+        }
+        X2PAgentError::Connection { host, .. } => {
+            span.record("x2p_error", "connection");
+            span.record("x2p_error_host", host.as_str());
+        }
+        X2PAgentError::AccessDenied { host, path } => {
+            span.record("x2p_error", "access_denied");
+            span.record("x2p_error_host", host.as_str());
+            span.record("x2p_error_path", path.as_str());
+        }
+        X2PAgentError::Error(_) => {
+            span.record("x2p_error", "error");
+        }
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(http::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
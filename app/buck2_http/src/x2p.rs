@@ -7,11 +7,113 @@
  * of this source tree.
  */
 
+use anyhow::Context as _;
 use http::HeaderMap;
 use http::HeaderValue;
 use http::Uri;
+use hyper_proxy::Intercept;
 use hyper_proxy::Proxy;
 
+use crate::no_proxy::NoProxy;
+use crate::socks5::Socks5ProxyConfig;
+
+/// A proxy selected by [`find_proxy`], which may tunnel via one or more
+/// plain HTTP `CONNECT` proxies (or unix sockets, internally) or via a
+/// SOCKS5 gateway.
+///
+/// `Http` carries every proxy that matched a distinct environment variable
+/// (`HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY`) rather than just the first,
+/// since a single `Proxy`'s `Intercept` can only select yes/no for one proxy
+/// -- it can't also choose *between* several. Register each with
+/// `hyper_proxy::ProxyConnector::add_proxy` so it picks the right one per
+/// request scheme.
+#[derive(Debug, Clone)]
+pub enum ProxyScheme {
+    Http(Vec<Proxy>),
+    Socks5(Socks5ProxyConfig),
+}
+
+/// Looks for a `ALL_PROXY`/`all_proxy` environment variable naming a
+/// `socks5://`/`socks5h://` gateway (e.g. a corporate SOCKS egress, or a
+/// local Tor/onion forwarder) and, if present, returns it ahead of any HTTP
+/// proxy. Available on both fbcode and non-fbcode builds.
+fn find_socks5_proxy() -> anyhow::Result<Option<ProxyScheme>> {
+    let Some(uri) = std::env::var("ALL_PROXY")
+        .ok()
+        .or_else(|| std::env::var("all_proxy").ok())
+    else {
+        return Ok(None);
+    };
+
+    if !uri.starts_with("socks5://") && !uri.starts_with("socks5h://") {
+        return Ok(None);
+    }
+
+    let proxy = Socks5ProxyConfig::parse(&uri)?;
+    tracing::debug!(
+        "Using SOCKS5 proxy client at {}:{}",
+        proxy.host,
+        proxy.port
+    );
+    Ok(Some(ProxyScheme::Socks5(proxy)))
+}
+
+/// Looks for proxies among the conventional `HTTP_PROXY`/`HTTPS_PROXY`/
+/// `ALL_PROXY` environment variables (and their lowercase equivalents),
+/// honoring `NO_PROXY`/`no_proxy` matching rules. Used on non-fbcode builds,
+/// which have no x2pagent to fall back on.
+///
+/// Each variable that's set is honored independently -- e.g. corporate
+/// setups commonly set both `HTTP_PROXY` and `HTTPS_PROXY` (often to the
+/// same URL), and both must apply, since https traffic (downloads, RE) is
+/// most of what buck2 sends.
+fn find_http_proxy_from_env() -> anyhow::Result<Vec<Proxy>> {
+    let no_proxy = NoProxy::from_env();
+
+    let candidates: &[(Intercept, &[&str])] = &[
+        (Intercept::Http, &["HTTP_PROXY", "http_proxy"]),
+        (Intercept::Https, &["HTTPS_PROXY", "https_proxy"]),
+        (Intercept::All, &["ALL_PROXY", "all_proxy"]),
+    ];
+
+    let mut proxies = Vec::new();
+    for (intercept, vars) in candidates {
+        let Some(uri) = vars.iter().find_map(|v| std::env::var(v).ok()) else {
+            continue;
+        };
+        let uri: Uri = uri
+            .parse()
+            .with_context(|| format!("Invalid proxy URI in environment: `{}`", uri))?;
+
+        tracing::debug!("Using {:?} proxy from environment: {}", intercept, uri);
+
+        let intercept = intercept.clone();
+        let no_proxy = no_proxy.clone();
+        let scheme_matches = move |scheme: Option<&str>, host: Option<&str>, port: Option<u16>| -> bool {
+            // `NO_PROXY` entries with a port only match the request's actual
+            // port; default that from the URI scheme, not a hardcoded 80,
+            // so e.g. `NO_PROXY=example.com:443` still bypasses the proxy
+            // for a port-less `https://example.com` request.
+            let port = port.unwrap_or(match scheme {
+                Some("https") => 443,
+                _ => 80,
+            });
+            if host.map_or(false, |host| no_proxy.matches_host_port(host, port)) {
+                return false;
+            }
+            match &intercept {
+                Intercept::Http => scheme == Some("http"),
+                Intercept::Https => scheme == Some("https"),
+                _ => true,
+            }
+        };
+
+        proxies.push(Proxy::new(Intercept::Custom(scheme_matches.into()), uri));
+    }
+
+    Ok(proxies)
+}
+
 #[cfg(fbcode_build)]
 mod imp {
     use anyhow::Context;
@@ -49,20 +151,31 @@ mod imp {
 }
 
 #[cfg(fbcode_build)]
-pub fn find_proxy() -> anyhow::Result<Option<Proxy>> {
+pub fn find_proxy() -> anyhow::Result<Option<ProxyScheme>> {
+    if let Some(proxy) = find_socks5_proxy()? {
+        return Ok(Some(proxy));
+    }
+
     #[cfg(unix)]
     if let Some(proxy) = imp::find_unix_socket_proxy() {
-        return Ok(Some(proxy));
+        return Ok(Some(ProxyScheme::Http(vec![proxy])));
     }
 
-    imp::find_http_proxy()
+    Ok(imp::find_http_proxy()?.map(|proxy| ProxyScheme::Http(vec![proxy])))
 }
 
 #[cfg(not(fbcode_build))]
-pub fn find_proxy() -> anyhow::Result<Option<Proxy>> {
-    Err(anyhow::anyhow!(
-        "VPNless development not supported for non-internal fbcode builds"
-    ))
+pub fn find_proxy() -> anyhow::Result<Option<ProxyScheme>> {
+    if let Some(proxy) = find_socks5_proxy()? {
+        return Ok(Some(proxy));
+    }
+
+    let proxies = find_http_proxy_from_env()?;
+    Ok(if proxies.is_empty() {
+        None
+    } else {
+        Some(ProxyScheme::Http(proxies))
+    })
 }
 
 /// Whether the machine buck is running on supports vpnless operation.